@@ -0,0 +1,284 @@
+//! A complementary decoder for HTTP chunked bodies, for use on the receiving end of a
+//! proxy or any other code that needs to strip chunked framing back off a stream
+//! produced by [`ChunkedWrite`](struct.ChunkedWrite.html) or any other chunked encoder.
+
+use std::cmp::min;
+use std::io::{self, Read};
+
+/// Chunk size lines (and trailer lines) longer than this are rejected as malformed
+/// rather than buffered without bound.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Tracks what part of the chunked grammar is being read next.
+enum State {
+    /// Reading the `chunk-size [ ";" chunk-ext ] CRLF` line.
+    Size,
+    /// Reading the remaining data bytes of the current chunk.
+    Data(usize),
+    /// Reading trailer fields (or the final blank line) after the zero-size chunk.
+    Trailer,
+    /// The chunked body, including trailers, has been fully consumed.
+    Done,
+}
+
+/// Reads the decoded bytes out of an HTTP [chunked request/response
+/// body](https://tools.ietf.org/html/rfc7230#section-4.1), stripping the chunk framing
+/// (sizes, extensions, and trailers) as it goes.
+///
+/// This is the inverse of [`ChunkedWrite`](struct.ChunkedWrite.html), letting the same
+/// crate decode a chunked body on one side of a proxy while encoding it on the other.
+///
+/// Dropping a `ChunkedRead` before it reaches EOF consumes the rest of the underlying
+/// chunked body first, so the source stream is left positioned just past the end of
+/// the body. If that draining hits a malformed body or an I/O error, it stops there
+/// and the source is left wherever the failed read left it.
+///
+/// Chunk-size and trailer lines are read one byte at a time, so reading directly off a
+/// raw [`TcpStream`](https://doc.rust-lang.org/stable/std/net/struct.TcpStream.html) (or
+/// any other unbuffered source) costs one syscall per line byte; wrap `src` in a
+/// [`BufReader`](https://doc.rust-lang.org/stable/std/io/struct.BufReader.html), for
+/// example `ChunkedRead::new(BufReader::new(stream))`, to amortize that cost.
+pub struct ChunkedRead<R: Read> {
+    src: R,
+    state: State,
+    line: [u8; MAX_LINE_LEN],
+    line_len: usize,
+}
+
+impl<R: Read> ChunkedRead<R> {
+    /// Create a new `ChunkedRead` decoding the chunked body read from `src`.
+    pub fn new(src: R) -> Self {
+        ChunkedRead {
+            src: src,
+            state: State::Size,
+            line: [0; MAX_LINE_LEN],
+            line_len: 0,
+        }
+    }
+
+    /// Read a single `\r`-stripped line (up to and including the terminating `\n`) off
+    /// the source into the fixed-size `self.line` buffer.
+    fn read_line(&mut self) -> io::Result<()> {
+        self.line_len = 0;
+        let mut byte = [0; 1];
+
+        loop {
+            let n = try!(self.src.read(&mut byte));
+
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected EOF in chunked body",
+                ));
+            }
+
+            if byte[0] == b'\n' {
+                return Ok(());
+            }
+
+            if byte[0] != b'\r' {
+                if self.line_len == self.line.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "chunked body line too long",
+                    ));
+                }
+
+                self.line[self.line_len] = byte[0];
+                self.line_len += 1;
+            }
+        }
+    }
+
+    /// Read the chunk-size line, discarding any `;`-prefixed chunk extensions, and
+    /// return the parsed chunk size.
+    fn read_size(&mut self) -> io::Result<usize> {
+        try!(self.read_line());
+
+        let line = &self.line[..self.line_len];
+
+        let size = match line.iter().position(|&b| b == b';') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+
+        let size = try!(std::str::from_utf8(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size line")));
+
+        usize::from_str_radix(size.trim(), 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size line"))
+    }
+
+    /// Consume the CRLF that follows a chunk's data bytes.
+    fn read_data_crlf(&mut self) -> io::Result<()> {
+        try!(self.read_line());
+
+        if self.line_len != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected CRLF after chunk data",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Consume trailer fields up to and including the final blank line.
+    fn read_trailers(&mut self) -> io::Result<()> {
+        loop {
+            try!(self.read_line());
+
+            if self.line_len == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self.state {
+            State::Done => true,
+            _ => false,
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let size = try!(self.read_size());
+
+                    self.state = if size == 0 {
+                        State::Trailer
+                    } else {
+                        State::Data(size)
+                    };
+                },
+                State::Data(0) => {
+                    try!(self.read_data_crlf());
+                    self.state = State::Size;
+                },
+                State::Data(remaining) => {
+                    let want = min(remaining, buf.len());
+
+                    if want == 0 {
+                        return Ok(0);
+                    }
+
+                    let n = try!(self.src.read(&mut buf[..want]));
+
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "unexpected EOF in chunk data",
+                        ));
+                    }
+
+                    self.state = State::Data(remaining - n);
+                    return Ok(n);
+                },
+                State::Trailer => {
+                    try!(self.read_trailers());
+                    self.state = State::Done;
+                },
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+impl<R: Read> Drop for ChunkedRead<R> {
+    fn drop(&mut self) {
+        // Drain whatever is left of the chunked body (data the caller never read, plus
+        // the framing) so the source stream ends up just past the body regardless of
+        // when the caller stopped reading.
+        let mut sink = [0; 64];
+
+        while !self.is_done() {
+            match self.read(&mut sink) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_chunked_read() {
+        let src = b"7\r\nabc def\r\na\r\ngh\nijklmno\r\n0\r\n\r\n";
+        let mut r = ChunkedRead::new(&src[..]);
+        let mut out = Vec::new();
+
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], b"abc defgh\nijklmno");
+    }
+
+    #[test]
+    fn test_chunked_read_small_buffer() {
+        let src = b"7\r\nabc def\r\na\r\ngh\nijklmno\r\n0\r\n\r\n";
+        let mut r = ChunkedRead::new(&src[..]);
+        let mut out = Vec::new();
+        let mut chunk = [0; 3];
+
+        loop {
+            let n = r.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&chunk[..n]);
+        }
+
+        assert_eq!(&out[..], b"abc defgh\nijklmno");
+    }
+
+    #[test]
+    fn test_chunked_read_extension() {
+        let src = b"3;foo=bar\r\nabc\r\n0\r\n\r\n";
+        let mut r = ChunkedRead::new(&src[..]);
+        let mut out = Vec::new();
+
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], b"abc");
+    }
+
+    #[test]
+    fn test_chunked_read_trailers() {
+        let src = b"3\r\nabc\r\n0\r\nDigest: abcd\r\nX-Foo: bar\r\n\r\n";
+        let mut r = ChunkedRead::new(&src[..]);
+        let mut out = Vec::new();
+
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], b"abc");
+    }
+
+    #[test]
+    fn test_chunked_read_malformed_size() {
+        let src = b"zz\r\nabc\r\n0\r\n\r\n";
+        let mut r = ChunkedRead::new(&src[..]);
+        let mut out = Vec::new();
+
+        assert!(r.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn test_chunked_read_drop_consumes_rest() {
+        let src = b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\nTRAILING";
+        let mut remaining: &[u8] = &src[..];
+
+        {
+            let mut r = ChunkedRead::new(&mut remaining);
+            let mut first = [0; 1];
+            r.read_exact(&mut first).unwrap();
+        }
+
+        let mut out = Vec::new();
+        remaining.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], b"TRAILING");
+    }
+}
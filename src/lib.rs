@@ -3,7 +3,9 @@
 //! directly into a
 //! [`TcpStream`](https://doc.rust-lang.org/stable/std/net/struct.TcpStream.html) or any
 //! other object that implements
-//! [`Write`](https://doc.rust-lang.org/stable/std/io/trait.Write.html).
+//! [`Write`](https://doc.rust-lang.org/stable/std/io/trait.Write.html). The
+//! complementary [`ChunkedRead`](struct.ChunkedRead.html) decodes a chunked body back
+//! into its original bytes, so the crate can be used on either side of a proxy.
 //!
 //! ## Example
 //!
@@ -21,7 +23,44 @@
 //! assert_eq!(&buf[..], &b"6\r\nhello \r\n4\r\n1337\r\n0\r\n\r\n"[..]);
 //! ```
 
-use std::io::Write;
+use std::io::{IoSlice, Write};
+
+mod decode;
+
+pub use decode::ChunkedRead;
+
+/// Write all of `bufs` to `sink`, retrying with the remaining, un-written slices (and
+/// the un-written remainder of a partially-written slice) until every byte has gone
+/// out, so a short `write_vectored` can never duplicate or drop part of the data.
+fn write_all_vectored<W: Write>(sink: &mut W, mut bufs: &mut [IoSlice]) -> std::io::Result<()> {
+    while !bufs.is_empty() {
+        match sink.write_vectored(bufs) {
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            },
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if `s` is a non-empty HTTP token (`tchar`+) as defined by
+/// [RFC 7230 §3.2.6](https://tools.ietf.org/html/rfc7230#section-3.2.6).
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Returns true if `s` contains any `\r` or `\n`, which would let the caller inject
+/// extra header/trailer lines or desync a decoder reading this stream.
+fn has_crlf(s: &str) -> bool {
+    s.bytes().any(|b| b == b'\r' || b == b'\n')
+}
 
 /// Writes bytes in the HTTP chunked encoding protocol.
 ///
@@ -31,39 +70,258 @@ use std::io::Write;
 /// To reduce the number of write syscalls to the underlying stream when using `write!` or
 /// byte-based serialization, wrap the object in a
 /// [`BufWriter`](https://doc.rust-lang.org/stable/std/io/struct.BufWriter.html), for
-/// example `BufWriter::new(ChunkedWrite::new(stream))`.
-pub struct ChunkedWrite<W: Write>(W);
+/// example `BufWriter::new(ChunkedWrite::new(stream))`, or use
+/// [`with_chunk_size`](#method.with_chunk_size) to have `ChunkedWrite` coalesce writes
+/// internally.
+pub struct ChunkedWrite<W: Write> {
+    sink: Option<W>,
+    buf: Option<Vec<u8>>,
+    cap: usize,
+}
 
 impl<W: Write> ChunkedWrite<W> {
     /// Create a new `ChunkedWrite` to write into the given stream.
+    ///
+    /// Every call to `write` emits its own chunk immediately.
     pub fn new(sink: W) -> Self {
-        ChunkedWrite(sink)
+        ChunkedWrite {
+            sink: Some(sink),
+            buf: None,
+            cap: 0,
+        }
+    }
+
+    /// Create a new `ChunkedWrite` that buffers incoming bytes internally and only emits
+    /// a chunk once `cap` bytes have accumulated.
+    ///
+    /// This coalesces small, repeated writes (such as those from `write!` or
+    /// byte-oriented serialization) into fewer, larger chunks without requiring the
+    /// caller to wrap the object in a `BufWriter`. A partial chunk smaller than `cap` is
+    /// flushed by `flush` or when the writer is finished.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cap` is zero, since no amount of buffered data would ever reach it.
+    pub fn with_chunk_size(sink: W, cap: usize) -> Self {
+        assert!(cap > 0, "with_chunk_size: cap must be nonzero");
+
+        ChunkedWrite {
+            sink: Some(sink),
+            buf: Some(Vec::with_capacity(cap)),
+            cap: cap,
+        }
+    }
+
+    /// Send the terminating empty chunk, flush the stream, and return the inner writer.
+    ///
+    /// Unlike letting the `ChunkedWrite` simply go out of scope, this surfaces any I/O
+    /// error from writing the final `0\r\n\r\n` or flushing the stream instead of
+    /// silently discarding it, so a caller can detect and handle a body that failed to
+    /// terminate cleanly.
+    pub fn finish(self) -> std::io::Result<W> {
+        self.finish_with_trailers(&[])
+    }
+
+    /// Like [`finish`](#method.finish), but emits the given `name: value` trailer
+    /// fields between the terminating chunk and the final `\r\n`, as described in
+    /// [RFC 7230 §4.1.2](https://tools.ietf.org/html/rfc7230#section-4.1.2).
+    ///
+    /// This is useful for streaming use cases where an integrity value, such as a
+    /// digest or checksum, is only known once the whole body has been generated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `io::Error` of kind `InvalidInput` without writing any trailer if any
+    /// trailer name isn't a valid HTTP token or any trailer value contains a `\r` or
+    /// `\n`, since either would let the trailers inject extra header lines. The
+    /// `ChunkedWrite` is still consumed, so as on drop the body is then terminated with
+    /// a bare `0\r\n\r\n`.
+    pub fn finish_with_trailers(mut self, trailers: &[(&str, &str)]) -> std::io::Result<W> {
+        for &(name, value) in trailers {
+            if !is_token(name) || has_crlf(value) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "invalid chunked trailer name or value",
+                ));
+            }
+        }
+
+        try!(self.flush_buf());
+        try!(write!(self.sink(), "0\r\n"));
+
+        for &(name, value) in trailers {
+            try!(write!(self.sink(), "{}: {}\r\n", name, value));
+        }
+
+        try!(write!(self.sink(), "\r\n"));
+        try!(self.sink().flush());
+
+        Ok(self.sink.take().unwrap())
+    }
+
+    /// Borrow the inner stream.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after `finish` has taken ownership of the stream, which cannot
+    /// happen through the public API since `finish` consumes `self`.
+    fn sink(&mut self) -> &mut W {
+        self.sink.as_mut().expect("ChunkedWrite used after finish")
+    }
+
+    /// Write `data` as a single chunk, attaching the given `chunk-ext` tokens to its
+    /// size line as permitted by
+    /// [RFC 7230 §4.1](https://tools.ietf.org/html/rfc7230#section-4.1), e.g.
+    /// `3;sig=abcd\r\nfoo\r\n`. Each extension is rendered as `;name` or `;name=value`.
+    ///
+    /// Unlike a plain `write`, which appends to the buffer of a buffered `ChunkedWrite`,
+    /// this first flushes any bytes already pending there as their own chunk, so the
+    /// extensions only ever end up on the chunk holding `data`.
+    pub fn write_chunk_with_ext(
+        &mut self,
+        data: &[u8],
+        ext: &[(&str, Option<&str>)],
+    ) -> std::io::Result<()> {
+        try!(self.flush_buf());
+        self.send_ext(data, ext)
     }
 
-    /// Send the given data in chunked encoding.
+    /// Send the given data as a single chunk, with no chunk extensions.
     fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
-        try!(write!(self.0, "{:x}\r\n", data.len()));
-        try!(self.0.write_all(data));
-        try!(write!(self.0, "\r\n"));
+        self.send_ext(data, &[])
+    }
+
+    /// Send the given data as a single chunk, with the given `chunk-ext` tokens on its
+    /// size line.
+    ///
+    /// The size line, data, and trailing CRLF are issued as a single vectored write
+    /// where possible, so a raw socket only has to do one `writev` instead of three
+    /// separate writes per chunk. If the write is short, the remaining slices are
+    /// retried until the whole chunk has gone out.
+    ///
+    /// Returns an `io::Error` of kind `InvalidInput` without writing anything if any
+    /// extension name or value isn't a valid HTTP token, since anything else (in
+    /// particular a `\r` or `\n`) would let the extensions desync the size line.
+    fn send_ext(&mut self, data: &[u8], ext: &[(&str, Option<&str>)]) -> std::io::Result<()> {
+        for &(name, value) in ext {
+            let value_ok = value.map_or(true, is_token);
+
+            if !is_token(name) || !value_ok {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "invalid chunk extension name or value",
+                ));
+            }
+        }
+
+        let mut head = format!("{:x}", data.len());
+
+        for &(name, value) in ext {
+            head.push(';');
+            head.push_str(name);
+
+            if let Some(value) = value {
+                head.push('=');
+                head.push_str(value);
+            }
+        }
 
-        Ok(())
+        head.push_str("\r\n");
+
+        let mut bufs = [
+            std::io::IoSlice::new(head.as_bytes()),
+            std::io::IoSlice::new(data),
+            std::io::IoSlice::new(b"\r\n"),
+        ];
+
+        write_all_vectored(self.sink(), &mut bufs)
+    }
+
+    /// Flush any buffered bytes out as a single chunk, if buffering is enabled and the
+    /// buffer is nonempty.
+    ///
+    /// The buffer's own backing storage is reused afterward rather than replaced, so the
+    /// `cap`-sized allocation made in `with_chunk_size` lives for the writer's whole
+    /// lifetime instead of being reallocated on every flush.
+    fn flush_buf(&mut self) -> std::io::Result<()> {
+        let mut chunk = match self.buf {
+            Some(ref mut buf) if !buf.is_empty() => std::mem::replace(buf, Vec::new()),
+            _ => return Ok(()),
+        };
+
+        let result = self.send(&chunk);
+        chunk.clear();
+        *self.buf.as_mut().unwrap() = chunk;
+
+        result
+    }
+
+    /// Append `data` to the internal buffer, flushing full `cap`-sized chunks as the
+    /// buffer fills up.
+    fn write_buffered(&mut self, mut data: &[u8]) -> std::io::Result<usize> {
+        let total = data.len();
+
+        while !data.is_empty() {
+            let space = self.cap - self.buf.as_ref().unwrap().len();
+
+            if data.len() < space {
+                self.buf.as_mut().unwrap().extend_from_slice(data);
+                break;
+            }
+
+            let (head, tail) = data.split_at(space);
+            self.buf.as_mut().unwrap().extend_from_slice(head);
+            try!(self.flush_buf());
+            data = tail;
+        }
+
+        Ok(total)
     }
 }
 
 impl<W: Write> Write for ChunkedWrite<W> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        try!(self.send(buf));
-        Ok(buf.len())
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.buf.is_some() {
+            self.write_buffered(data)
+        } else {
+            try!(self.send(data));
+            Ok(data.len())
+        }
     }
 
-    fn flush(&mut self) -> std::io::Result<()> { self.0.flush() }
+    fn flush(&mut self) -> std::io::Result<()> {
+        try!(self.flush_buf());
+        self.sink().flush()
+    }
 }
 
 impl<W: Write> Drop for ChunkedWrite<W> {
     fn drop(&mut self) {
-        // Send terminating empty chunk and flush the stream.
-        self.send(&[]).is_ok();
-        self.flush().is_ok();
+        // Once `finish` has succeeded, the stream has been taken and there is nothing
+        // left to terminate or flush. If `finish` returned an error, the stream is still
+        // here and is terminated and flushed below like any other dropped writer.
+        if self.sink.is_some() {
+            self.flush_buf().is_ok();
+            self.send(&[]).is_ok();
+            self.flush().is_ok();
+        }
+    }
+}
+
+/// A `Write` that only relies on the default, non-overridden `write_vectored` (which
+/// writes at most the first slice) and only accepts one byte per call, to exercise the
+/// short-write path of a vectored send.
+#[cfg(test)]
+struct OneByteAtATime<W>(W);
+
+#[cfg(test)]
+impl<W: Write> Write for OneByteAtATime<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.write(&data[..1])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
     }
 }
 
@@ -84,4 +342,106 @@ mod test {
 
         assert_eq!(&buf[..], b"7\r\nabc def\r\na\r\ngh\nijklmno\r\n0\r\n\r\n");
     }
+
+    #[test]
+    fn test_chunked_write_with_chunk_size() {
+        let mut buf = [0; 47];
+
+        {
+            let mut w = ChunkedWrite::with_chunk_size(&mut buf[..], 4);
+            w.write_all(b"abc def").unwrap();
+            w.write_all(b"gh\nijklmno").unwrap();
+        }
+
+        // "abc def" + "gh\nijklmno" buffered in chunks of 4, with the final partial
+        // chunk flushed on drop.
+        assert_eq!(
+            &buf[..],
+            b"4\r\nabc \r\n4\r\ndefg\r\n4\r\nh\nij\r\n4\r\nklmn\r\n1\r\no\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_chunked_write_with_chunk_size_flush() {
+        let mut buf = [0; 21];
+
+        {
+            let mut w = ChunkedWrite::with_chunk_size(&mut buf[..], 8);
+            w.write_all(b"abc").unwrap();
+            w.flush().unwrap();
+            w.write_all(b"def").unwrap();
+        }
+
+        assert_eq!(&buf[..], b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_chunked_write_finish() {
+        let mut buf = [0; 32];
+
+        {
+            let mut w = ChunkedWrite::new(&mut buf[..]);
+            w.write_all(b"abc def").unwrap();
+            w.write_all(b"gh\nijklmno").unwrap();
+            w.finish().unwrap();
+        }
+
+        assert_eq!(&buf[..], b"7\r\nabc def\r\na\r\ngh\nijklmno\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_chunked_write_finish_with_trailers() {
+        let mut buf = [0; 43];
+
+        {
+            let mut w = ChunkedWrite::new(&mut buf[..]);
+            w.write_all(b"abc def").unwrap();
+            w.finish_with_trailers(&[("Digest", "abcd"), ("X-Foo", "bar")]).unwrap();
+        }
+
+        assert_eq!(
+            &buf[..],
+            b"7\r\nabc def\r\n0\r\nDigest: abcd\r\nX-Foo: bar\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_chunked_write_finish_with_trailers_rejects_crlf() {
+        let mut buf = [0; 43];
+
+        let w = ChunkedWrite::new(&mut buf[..]);
+        assert!(w.finish_with_trailers(&[("X-Foo", "bar\r\nEvil: true")]).is_err());
+    }
+
+    #[test]
+    fn test_chunked_write_chunk_with_ext() {
+        let mut buf = [0; 27];
+
+        {
+            let mut w = ChunkedWrite::new(&mut buf[..]);
+            w.write_chunk_with_ext(b"abc", &[("sig", Some("abcd")), ("last", None)]).unwrap();
+        }
+
+        assert_eq!(&buf[..], b"3;sig=abcd;last\r\nabc\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_chunked_write_chunk_with_ext_rejects_crlf() {
+        let mut buf = [0; 27];
+
+        let mut w = ChunkedWrite::new(&mut buf[..]);
+        assert!(w.write_chunk_with_ext(b"abc", &[("sig", Some("abcd\r\nEvil: true"))]).is_err());
+    }
+
+    #[test]
+    fn test_chunked_write_short_vectored_write() {
+        let mut buf = [0; 13];
+
+        {
+            let mut w = ChunkedWrite::new(OneByteAtATime(&mut buf[..]));
+            w.write_all(b"abc").unwrap();
+        }
+
+        assert_eq!(&buf[..], b"3\r\nabc\r\n0\r\n\r\n");
+    }
 }